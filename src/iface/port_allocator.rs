@@ -0,0 +1,92 @@
+use crate::wire::{IpAddress, IpProtocol};
+
+use super::DispatchTable;
+
+/// Default ephemeral port range, matching the IANA dynamic/private port range used by
+/// most operating systems.
+const DEFAULT_EPHEMERAL_RANGE: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Hands out free local ports for UDP/TCP sockets that bind or connect with port 0.
+///
+/// Consults the [`DispatchTable`] so that a port already bound by another socket is
+/// skipped; `add_udp_socket`/`add_tcp_socket`'s `AddError::AlreadyInUse` remains the
+/// final safety net rather than the primary collision check.
+#[derive(Debug, Clone)]
+pub(crate) struct PortAllocator {
+    range: core::ops::RangeInclusive<u16>,
+    cursor: u16,
+}
+
+impl Default for PortAllocator {
+    fn default() -> Self {
+        PortAllocator::new(DEFAULT_EPHEMERAL_RANGE)
+    }
+}
+
+impl PortAllocator {
+    pub(crate) fn new(range: core::ops::RangeInclusive<u16>) -> Self {
+        PortAllocator {
+            cursor: *range.start(),
+            range,
+        }
+    }
+
+    /// Returns a free port for `protocol` at `local_addr`, rotating the cursor across
+    /// calls so successive allocations spread out over the ephemeral range. Returns
+    /// `None` if a full lap of the range finds no free port.
+    pub(crate) fn allocate(
+        &mut self,
+        dispatch_table: &DispatchTable,
+        protocol: IpProtocol,
+        local_addr: IpAddress,
+    ) -> Option<u16> {
+        // Computed in `u32` because the full `0..=65535` range has a span of 65536,
+        // which overflows `u16`.
+        let span = u32::from(*self.range.end()) - u32::from(*self.range.start()) + 1;
+
+        for _ in 0..span {
+            let port = self.cursor;
+            self.cursor = if self.cursor == *self.range.end() {
+                *self.range.start()
+            } else {
+                self.cursor + 1
+            };
+
+            if !dispatch_table.is_port_in_use(protocol, local_addr, port) {
+                return Some(port);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::Ipv4Address;
+
+    #[test]
+    fn wraps_around_the_configured_range() {
+        let mut allocator = PortAllocator::new(49152..=49154);
+        let table = DispatchTable::default();
+        let addr = IpAddress::Ipv4(Ipv4Address::UNSPECIFIED);
+
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Tcp, addr),
+            Some(49152)
+        );
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Tcp, addr),
+            Some(49153)
+        );
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Tcp, addr),
+            Some(49154)
+        );
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Tcp, addr),
+            Some(49152)
+        );
+    }
+}
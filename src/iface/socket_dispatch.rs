@@ -1,12 +1,58 @@
 use std::collections::btree_map::Entry;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
 
-use crate::socket::{raw, tcp, udp};
+use crate::socket::{icmp, raw, tcp, udp};
 use crate::wire::{
-    IpAddress, IpEndpoint, IpListenEndpoint, IpProtocol, IpVersion, Ipv4Address, Ipv6Address,
+    IpAddress, IpEndpoint, IpListenEndpoint, IpProtocol, IpRepr, IpVersion, Ipv4Address,
+    Ipv6Address, TcpRepr,
 };
 
-use super::SocketHandle;
+use super::{PortAllocator, SocketHandle};
+
+/// Picks a listener among `listen_sockets` by hashing the connection 4-tuple, giving
+/// REUSEPORT-style load balancing. Takes an iterator rather than a `&BTreeSet` so
+/// callers can pass a filtered view (e.g. dual-stack-only listeners) without collecting
+/// into a temporary set on every packet. Because the source iterates in `SocketHandle`
+/// order (as a `BTreeSet`'s does) and visits the same members each call, the same
+/// 4-tuple always maps to the same listener as long as the underlying set's membership
+/// is unchanged, which is the invariant TCP flows (and SYN retransmits) need.
+fn select_listener(
+    listen_sockets: impl Iterator<Item = SocketHandle> + Clone,
+    ip_repr: &crate::wire::IpRepr,
+    tcp_repr: &crate::wire::TcpRepr,
+) -> Option<SocketHandle> {
+    let len = listen_sockets.clone().count();
+    if len <= 1 {
+        return listen_sockets.into_iter().next();
+    }
+
+    let mut hasher = DefaultHasher::new();
+    ip_repr.src_addr().hash(&mut hasher);
+    tcp_repr.src_port.hash(&mut hasher);
+    ip_repr.dst_addr().hash(&mut hasher);
+    tcp_repr.dst_port.hash(&mut hasher);
+    let index = (hasher.finish() as usize) % len;
+
+    listen_sockets.into_iter().nth(index)
+}
+
+/// Maps an IPv4 address to its IPv4-mapped IPv6 form (`::ffff:a.b.c.d`), used to key
+/// dual-stack sockets' established connections and peers consistently in IPv6 terms.
+fn ipv4_mapped_ipv6(addr: Ipv4Address) -> Ipv6Address {
+    let octets = addr.octets();
+    Ipv6Address::new(
+        0,
+        0,
+        0,
+        0,
+        0,
+        0xffff,
+        u16::from_be_bytes([octets[0], octets[1]]),
+        u16::from_be_bytes([octets[2], octets[3]]),
+    )
+}
 
 #[derive(Debug, Default)]
 struct TcpLocalEndpoint {
@@ -16,13 +62,29 @@ struct TcpLocalEndpoint {
 
 #[derive(Debug, Default)]
 pub struct DispatchTable {
-    raw: BTreeMap<(IpVersion, IpProtocol), SocketHandle>,
+    raw: BTreeMap<(IpVersion, IpProtocol), BTreeSet<SocketHandle>>,
     udp: BTreeMap<IpListenEndpoint, SocketHandle>,
     tcp: BTreeMap<IpListenEndpoint, TcpLocalEndpoint>,
 
+    /// ICMP sockets bound to an identifier, keyed by `(ip_version, ident)` so echo
+    /// replies are routed by ICMP identifier.
+    icmp_ident: BTreeMap<(IpVersion, u16), SocketHandle>,
+    /// ICMP sockets bound to a UDP-style endpoint, so ICMP error messages referencing a
+    /// transport flow reach the socket that owns that flow.
+    icmp_udp: BTreeMap<IpListenEndpoint, SocketHandle>,
+
     rev_raw: BTreeMap<SocketHandle, (IpVersion, IpProtocol)>,
     rev_udp: BTreeMap<SocketHandle, IpListenEndpoint>,
     rev_tcp: BTreeMap<SocketHandle, (IpListenEndpoint, Option<IpEndpoint>)>,
+    rev_icmp: BTreeMap<SocketHandle, (IpVersion, icmp::Endpoint)>,
+
+    /// Handles of TCP/UDP sockets added in dual-stack mode. Only those bound to the IPv6
+    /// unspecified address are consulted, so they additionally accept IPv4 traffic
+    /// addressed to their port.
+    dual_stack: BTreeSet<SocketHandle>,
+
+    /// Hands out ephemeral ports for UDP/TCP sockets that bind or connect with port 0.
+    port_allocator: PortAllocator,
 }
 
 impl DispatchTable {
@@ -48,13 +110,63 @@ impl DispatchTable {
                     },
                     tcp_repr.dst_port,
                 )))
-            })?;
+            });
 
-        local_endpoint
+        if let Some(local_endpoint) = local_endpoint {
+            let remote_endpoint = IpEndpoint::new(ip_repr.src_addr(), tcp_repr.src_port);
+            if let Some(handle) = local_endpoint
+                .established_sockets
+                .get(&remote_endpoint)
+                .copied()
+                .or_else(|| {
+                    select_listener(
+                        local_endpoint.listen_sockets.iter().copied(),
+                        ip_repr,
+                        tcp_repr,
+                    )
+                })
+            {
+                return Some(handle);
+            }
+        }
+
+        // Dual-stack fallback: an IPv6 wildcard listener opted into dual-stack mode also
+        // accepts IPv4 traffic, keyed by the peer's IPv4-mapped IPv6 form.
+        let IpAddress::Ipv4(remote_addr) = ip_repr.src_addr() else {
+            return None;
+        };
+        let local_endpoint = self.tcp.get(&IpListenEndpoint::from(IpEndpoint::new(
+            IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+            tcp_repr.dst_port,
+        )))?;
+        let mapped_remote_endpoint = IpEndpoint::new(
+            IpAddress::Ipv6(ipv4_mapped_ipv6(remote_addr)),
+            tcp_repr.src_port,
+        );
+
+        if let Some(&handle) = local_endpoint
             .established_sockets
-            .get(&IpEndpoint::new(ip_repr.src_addr(), tcp_repr.src_port))
-            .or_else(|| local_endpoint.listen_sockets.iter().next())
-            .copied()
+            .get(&mapped_remote_endpoint)
+        {
+            if self.dual_stack.contains(&handle) {
+                return Some(handle);
+            }
+        }
+
+        // Filter to dual-stack listeners before hashing: a REUSEPORT group may mix
+        // dual-stack and non-dual-stack members, and hashing across all of them could
+        // land on a non-dual-stack sibling even though a dual-stack one could have
+        // served this IPv4-mapped connection. Filtered lazily (no intermediate set) since
+        // this runs on every IPv4 segment that reaches the dual-stack fallback.
+        select_listener(
+            local_endpoint
+                .listen_sockets
+                .iter()
+                .copied()
+                .filter(|handle| self.dual_stack.contains(handle)),
+            ip_repr,
+            tcp_repr,
+        )
     }
 
     pub(crate) fn get_udp_socket(
@@ -62,7 +174,8 @@ impl DispatchTable {
         ip_repr: &crate::wire::IpRepr,
         udp_repr: &crate::wire::UdpRepr,
     ) -> Option<SocketHandle> {
-        self.udp
+        let handle = self
+            .udp
             .get(&IpListenEndpoint::from(IpEndpoint::new(
                 // bound address and port
                 ip_repr.dst_addr(),
@@ -79,16 +192,97 @@ impl DispatchTable {
                     udp_repr.dst_port,
                 )))
             })
+            .copied();
+
+        if handle.is_some() {
+            return handle;
+        }
+
+        // Dual-stack fallback: an IPv6 wildcard listener opted into dual-stack mode also
+        // accepts IPv4 traffic addressed to its port.
+        let IpAddress::Ipv4(_) = ip_repr.dst_addr() else {
+            return None;
+        };
+        self.udp
+            .get(&IpListenEndpoint::from(IpEndpoint::new(
+                IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+                udp_repr.dst_port,
+            )))
             .copied()
+            .filter(|handle| self.dual_stack.contains(handle))
     }
 
+    /// Returns all raw sockets registered for `(ip_version, ip_protocol)`, so that an
+    /// incoming packet can be fanned out to every interested socket rather than just
+    /// the first one registered.
     pub(crate) fn get_raw_socket(
         &self,
         ip_version: crate::wire::IpVersion,
         ip_protocol: crate::wire::IpProtocol,
-    ) -> Option<SocketHandle> {
+    ) -> impl Iterator<Item = SocketHandle> + '_ {
         let key = (ip_version, ip_protocol);
-        self.raw.get(&key).copied()
+        self.raw.get(&key).into_iter().flatten().copied()
+    }
+
+    /// Returns the ICMP socket matching `endpoint`: by ICMP identifier for echo replies
+    /// (`icmp::Endpoint::Ident`), or by the UDP-style endpoint of the transport flow an
+    /// ICMP error message refers to (`icmp::Endpoint::Udp`).
+    pub(crate) fn get_icmp_socket(
+        &self,
+        ip_version: IpVersion,
+        endpoint: &icmp::Endpoint,
+    ) -> Option<SocketHandle> {
+        match endpoint {
+            icmp::Endpoint::Ident(ident) => self.icmp_ident.get(&(ip_version, *ident)).copied(),
+            icmp::Endpoint::Udp(udp_endpoint) => self.icmp_udp.get(udp_endpoint).copied(),
+            icmp::Endpoint::Unspecified => None,
+        }
+    }
+
+    /// Returns whether `port` is already bound for `protocol` at `local_addr`, checking
+    /// both the exact address and its wildcard forms. Used by `PortAllocator` to find a
+    /// free ephemeral port without scanning the whole socket set.
+    pub(crate) fn is_port_in_use(
+        &self,
+        protocol: IpProtocol,
+        local_addr: IpAddress,
+        port: u16,
+    ) -> bool {
+        let wildcard_addr = match local_addr.version() {
+            IpVersion::Ipv4 => IpAddress::Ipv4(Ipv4Address::UNSPECIFIED),
+            IpVersion::Ipv6 => IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+        };
+
+        let bound = IpListenEndpoint::from(IpEndpoint::new(local_addr, port));
+        let bound_wildcard = IpListenEndpoint::from(IpEndpoint::new(wildcard_addr, port));
+        let port_only = IpListenEndpoint::from(port);
+
+        match protocol {
+            IpProtocol::Udp => {
+                self.udp.contains_key(&bound)
+                    || self.udp.contains_key(&bound_wildcard)
+                    || self.udp.contains_key(&port_only)
+            }
+            IpProtocol::Tcp => {
+                self.tcp.contains_key(&bound)
+                    || self.tcp.contains_key(&bound_wildcard)
+                    || self.tcp.contains_key(&port_only)
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a free ephemeral port for `protocol` at `local_addr`, for a socket that
+    /// binds or connects with port 0.
+    pub(crate) fn allocate_ephemeral_port(
+        &mut self,
+        protocol: IpProtocol,
+        local_addr: IpAddress,
+    ) -> Option<u16> {
+        let mut port_allocator = std::mem::take(&mut self.port_allocator);
+        let port = port_allocator.allocate(self, protocol, local_addr);
+        self.port_allocator = port_allocator;
+        port
     }
 }
 
@@ -104,17 +298,21 @@ impl DispatchTable {
         handle: SocketHandle,
     ) -> Result<(), AddError> {
         let key = (socket.ip_version(), socket.ip_protocol());
+
+        let rev_entry = match self.rev_raw.entry(handle) {
+            Entry::Occupied(_) => return Err(AddError::AlreadyInUse),
+            Entry::Vacant(e) => e,
+        };
+
         net_trace!(
             "added raw socket to dispatch table at (ip_version, ip_protocol) {:?}",
             key
         );
-        match (self.raw.entry(key), self.rev_raw.entry(handle)) {
-            (Entry::Vacant(e), Entry::Vacant(re)) => {
-                e.insert(handle);
-                re.insert(key);
-            }
-            _ => return Err(AddError::AlreadyInUse),
-        };
+
+        // Several raw sockets may share the same (ip_version, ip_protocol), e.g. for
+        // packet sniffing, so they are kept in a set rather than replacing each other.
+        self.raw.entry(key).or_default().insert(handle);
+        rev_entry.insert(key);
         Ok(())
     }
 
@@ -122,6 +320,7 @@ impl DispatchTable {
         &mut self,
         socket: &udp::Socket<'_>,
         handle: SocketHandle,
+        dual_stack: bool,
     ) -> Result<(), AddError> {
         if !socket.endpoint().is_specified() && socket.endpoint().port == 0 {
             return Ok(());
@@ -142,6 +341,10 @@ impl DispatchTable {
             }
             _ => return Err(AddError::AlreadyInUse),
         };
+
+        if dual_stack {
+            self.dual_stack.insert(handle);
+        }
         Ok(())
     }
 
@@ -149,6 +352,7 @@ impl DispatchTable {
         &mut self,
         socket: &tcp::Socket<'_>,
         handle: SocketHandle,
+        dual_stack: bool,
     ) -> Result<(), AddError> {
         let Some(listen_endpoint) = socket
             .listen_endpoint()
@@ -182,6 +386,50 @@ impl DispatchTable {
             rev_entry.insert((listen_endpoint, None));
         }
 
+        if dual_stack {
+            self.dual_stack.insert(handle);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn add_icmp_socket(
+        &mut self,
+        socket: &icmp::Socket<'_>,
+        ip_version: IpVersion,
+        handle: SocketHandle,
+    ) -> Result<(), AddError> {
+        let endpoint = socket.endpoint();
+        if matches!(endpoint, icmp::Endpoint::Unspecified) {
+            return Ok(());
+        }
+
+        let rev_entry = match self.rev_icmp.entry(handle) {
+            Entry::Occupied(_) => return Err(AddError::AlreadyInUse),
+            Entry::Vacant(e) => e,
+        };
+
+        net_trace!(
+            "added icmp socket to dispatch table at endpoint {:?}",
+            endpoint
+        );
+
+        match endpoint {
+            icmp::Endpoint::Ident(ident) => match self.icmp_ident.entry((ip_version, ident)) {
+                Entry::Vacant(e) => {
+                    e.insert(handle);
+                }
+                Entry::Occupied(_) => return Err(AddError::AlreadyInUse),
+            },
+            icmp::Endpoint::Udp(udp_endpoint) => match self.icmp_udp.entry(udp_endpoint) {
+                Entry::Vacant(e) => {
+                    e.insert(handle);
+                }
+                Entry::Occupied(_) => return Err(AddError::AlreadyInUse),
+            },
+            icmp::Endpoint::Unspecified => unreachable!(),
+        }
+
+        rev_entry.insert((ip_version, endpoint));
         Ok(())
     }
 }
@@ -197,8 +445,13 @@ impl DispatchTable {
             Entry::Vacant(_) => Err(RemoveError::SocketNotFound),
             Entry::Occupied(re) => match self.raw.entry(*re.get()) {
                 Entry::Vacant(_) => Err(RemoveError::SocketNotFound),
-                Entry::Occupied(e) => {
-                    e.remove();
+                Entry::Occupied(mut e) => {
+                    if !e.get_mut().remove(&handle) {
+                        return Err(RemoveError::SocketNotFound);
+                    }
+                    if e.get().is_empty() {
+                        e.remove();
+                    }
                     re.remove();
                     Ok(())
                 }
@@ -214,6 +467,7 @@ impl DispatchTable {
                 Entry::Occupied(e) => {
                     e.remove();
                     re.remove();
+                    self.dual_stack.remove(&handle);
                     Ok(())
                 }
             },
@@ -261,6 +515,191 @@ impl DispatchTable {
             tc_endpoint_entry.remove();
         }
 
+        self.dual_stack.remove(&handle);
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub(crate) fn remove_icmp_socket(&mut self, handle: SocketHandle) -> Result<(), RemoveError> {
+        match self.rev_icmp.entry(handle) {
+            Entry::Vacant(_) => Err(RemoveError::SocketNotFound),
+            Entry::Occupied(re) => {
+                let &(ip_version, endpoint) = re.get();
+                let removed = match endpoint {
+                    icmp::Endpoint::Ident(ident) => {
+                        self.icmp_ident.remove(&(ip_version, ident)).is_some()
+                    }
+                    icmp::Endpoint::Udp(udp_endpoint) => {
+                        self.icmp_udp.remove(&udp_endpoint).is_some()
+                    }
+                    icmp::Endpoint::Unspecified => false,
+                };
+
+                if !removed {
+                    return Err(RemoveError::SocketNotFound);
+                }
+                re.remove();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{Ipv4Repr, SeqNumber, TcpControl};
+
+    fn segment(
+        src_addr: Ipv4Address,
+        src_port: u16,
+        dst_addr: Ipv4Address,
+        dst_port: u16,
+    ) -> (IpRepr, TcpRepr<'static>) {
+        let ip_repr = IpRepr::Ipv4(Ipv4Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Tcp,
+            payload_len: 0,
+            hop_limit: 64,
+        });
+        let tcp_repr = TcpRepr {
+            src_port,
+            dst_port,
+            control: TcpControl::None,
+            seq_number: SeqNumber(0),
+            ack_number: None,
+            window_len: 0,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None, None, None],
+            payload: &[],
+        };
+        (ip_repr, tcp_repr)
+    }
+
+    #[test]
+    fn select_listener_is_stable_for_the_same_four_tuple() {
+        let listeners: BTreeSet<SocketHandle> = [SocketHandle(0), SocketHandle(1), SocketHandle(2)]
+            .into_iter()
+            .collect();
+        let (ip_repr, tcp_repr) = segment(
+            Ipv4Address::new(192, 0, 2, 1),
+            51234,
+            Ipv4Address::new(192, 0, 2, 2),
+            80,
+        );
+
+        let first = select_listener(listeners.iter().copied(), &ip_repr, &tcp_repr);
+        let second = select_listener(listeners.iter().copied(), &ip_repr, &tcp_repr);
+        assert!(first.is_some());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn select_listener_spreads_across_different_four_tuples() {
+        let listeners: BTreeSet<SocketHandle> = [
+            SocketHandle(0),
+            SocketHandle(1),
+            SocketHandle(2),
+            SocketHandle(3),
+        ]
+        .into_iter()
+        .collect();
+
+        let mut picked = BTreeSet::new();
+        for client_port in 0..32u16 {
+            let (ip_repr, tcp_repr) = segment(
+                Ipv4Address::new(192, 0, 2, 1),
+                50000 + client_port,
+                Ipv4Address::new(192, 0, 2, 2),
+                80,
+            );
+            picked.insert(select_listener(listeners.iter().copied(), &ip_repr, &tcp_repr).unwrap());
+        }
+
+        assert!(picked.len() > 1);
+    }
+
+    #[test]
+    fn select_listener_picks_the_only_member() {
+        let listeners: BTreeSet<SocketHandle> = [SocketHandle(7)].into_iter().collect();
+        let (ip_repr, tcp_repr) = segment(
+            Ipv4Address::new(192, 0, 2, 1),
+            51234,
+            Ipv4Address::new(192, 0, 2, 2),
+            80,
+        );
+
+        assert_eq!(
+            select_listener(listeners.iter().copied(), &ip_repr, &tcp_repr),
+            Some(SocketHandle(7))
+        );
+    }
+
+    #[test]
+    fn select_listener_empty_set_yields_none() {
+        let listeners: BTreeSet<SocketHandle> = BTreeSet::new();
+        let (ip_repr, tcp_repr) = segment(
+            Ipv4Address::new(192, 0, 2, 1),
+            51234,
+            Ipv4Address::new(192, 0, 2, 2),
+            80,
+        );
+
+        assert_eq!(
+            select_listener(listeners.iter().copied(), &ip_repr, &tcp_repr),
+            None
+        );
+    }
+
+    #[test]
+    fn port_allocator_skips_ports_already_bound() {
+        let addr = IpAddress::Ipv4(Ipv4Address::UNSPECIFIED);
+        let mut table = DispatchTable {
+            udp: BTreeMap::from([(
+                IpListenEndpoint::from(IpEndpoint::new(addr, 49152)),
+                SocketHandle(0),
+            )]),
+            ..Default::default()
+        };
+        let mut allocator = PortAllocator::new(49152..=49154);
+
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Udp, addr),
+            Some(49153)
+        );
+
+        // 49153 was handed out but never bound, so after skipping 49154 and wrapping
+        // past the bound 49152 it is free again.
+        table.udp.insert(
+            IpListenEndpoint::from(IpEndpoint::new(addr, 49154)),
+            SocketHandle(1),
+        );
+        assert_eq!(
+            allocator.allocate(&table, IpProtocol::Udp, addr),
+            Some(49153)
+        );
+    }
+
+    #[test]
+    fn port_allocator_returns_none_once_range_is_exhausted() {
+        let addr = IpAddress::Ipv4(Ipv4Address::UNSPECIFIED);
+        let table = DispatchTable {
+            udp: BTreeMap::from([
+                (
+                    IpListenEndpoint::from(IpEndpoint::new(addr, 49152)),
+                    SocketHandle(0),
+                ),
+                (
+                    IpListenEndpoint::from(IpEndpoint::new(addr, 49153)),
+                    SocketHandle(1),
+                ),
+            ]),
+            ..Default::default()
+        };
+        let mut allocator = PortAllocator::new(49152..=49153);
+
+        assert_eq!(allocator.allocate(&table, IpProtocol::Udp, addr), None);
+    }
+}
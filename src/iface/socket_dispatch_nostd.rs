@@ -21,7 +21,24 @@ impl DispatchTable {
         &self,
         ip_version: crate::wire::IpVersion,
         ip_protocol: crate::wire::IpProtocol,
+    ) -> impl Iterator<Item = SocketHandle> {
+        unimplemented!()
+    }
+
+    pub(crate) fn get_icmp_socket(
+        &self,
+        ip_version: crate::wire::IpVersion,
+        endpoint: &crate::socket::icmp::Endpoint,
     ) -> Option<SocketHandle> {
         unimplemented!()
     }
+
+    pub(crate) fn is_port_in_use(
+        &self,
+        protocol: crate::wire::IpProtocol,
+        local_addr: crate::wire::IpAddress,
+        port: u16,
+    ) -> bool {
+        unimplemented!()
+    }
 }
\ No newline at end of file
@@ -64,12 +64,14 @@ impl<'a> TrackedSocket for udp::Socket<'a> {
         dispatch_table: &mut DispatchTable,
         handle: SocketHandle,
     ) {
+        self.assign_ephemeral_port(dispatch_table);
+
         if old_endpoint != self.endpoint() {
             if old_endpoint.is_specified() {
                 let res = dispatch_table.remove_udp_socket(handle);
                 debug_assert!(res.is_ok());
             }
-            let res = dispatch_table.add_udp_socket(self, handle);
+            let res = dispatch_table.add_udp_socket(self, handle, self.is_dual_stack());
             debug_assert!(res.is_ok());
         }
     }
@@ -100,6 +102,8 @@ impl<'a> TrackedSocket for tcp::Socket<'a> {
         dispatch_table: &mut DispatchTable,
         handle: SocketHandle,
     ) {
+        self.assign_ephemeral_port(dispatch_table);
+
         if state == &self.state() {
             return;
         }
@@ -109,13 +113,13 @@ impl<'a> TrackedSocket for tcp::Socket<'a> {
                 debug_assert!(res.is_ok());
             }
             (tcp::State::Closed, _) => {
-                let res = dispatch_table.add_tcp_socket(self, handle);
+                let res = dispatch_table.add_tcp_socket(self, handle, self.is_dual_stack());
                 debug_assert!(res.is_ok());
             }
             (tcp::State::TimeWait, _) | (tcp::State::Listen, _) => {
                 let res = dispatch_table.remove_tcp_socket(handle);
                 debug_assert!(res.is_ok());
-                let res = dispatch_table.add_tcp_socket(self, handle);
+                let res = dispatch_table.add_tcp_socket(self, handle, self.is_dual_stack());
                 debug_assert!(res.is_ok());
             }
             (_, _) => {}
@@ -135,26 +139,45 @@ impl<'a> TrackedSocket for tcp::Socket<'a> {
     }
 }
 
-/// These sockets do not yet have dispatch tables, TrackedSocket implementation is empty
-
 impl<'a> TrackedSocket for icmp::Socket<'a> {
-    type State = ();
+    type State = (crate::wire::IpVersion, icmp::Endpoint);
 
     fn new_state(&self) -> Self::State {
-        ()
+        (self.ip_version(), self.endpoint())
+    }
+
+    fn on_drop(
+        &mut self,
+        &(old_ip_version, old_endpoint): &Self::State,
+        dispatch_table: &mut DispatchTable,
+        handle: SocketHandle,
+    ) {
+        let new_state = (self.ip_version(), self.endpoint());
+        if (old_ip_version, old_endpoint) != new_state {
+            if old_endpoint != icmp::Endpoint::Unspecified {
+                let res = dispatch_table.remove_icmp_socket(handle);
+                debug_assert!(res.is_ok());
+            }
+            let res = dispatch_table.add_icmp_socket(self, new_state.0, handle);
+            debug_assert!(res.is_ok());
+        }
     }
 
     fn is_dirty(&self) -> bool {
-        false
+        self.is_dirty()
     }
 
     fn is_on_dirty_list(&self) -> bool {
-        false
+        self.is_on_dirty_list()
     }
 
-    fn set_on_dirty_list(&mut self, _is_dirty: bool) {}
+    fn set_on_dirty_list(&mut self, is_dirty: bool) {
+        self.set_on_dirty_list(is_dirty)
+    }
 }
 
+/// These sockets do not yet have dispatch tables, TrackedSocket implementation is empty
+
 impl<'a> TrackedSocket for dhcpv4::Socket<'a> {
     type State = ();
 
@@ -227,6 +250,7 @@ pub enum SocketState<'a> {
     Raw(<raw::Socket<'a> as TrackedSocket>::State),
     Udp(<udp::Socket<'a> as TrackedSocket>::State),
     Tcp(<tcp::Socket<'a> as TrackedSocket>::State),
+    Icmp(<icmp::Socket<'a> as TrackedSocket>::State),
 }
 
 impl<'a> TrackedSocket for crate::socket::Socket<'a> {
@@ -237,6 +261,7 @@ impl<'a> TrackedSocket for crate::socket::Socket<'a> {
             crate::socket::Socket::Raw(socket) => SocketState::Raw(socket.new_state()),
             crate::socket::Socket::Udp(socket) => SocketState::Udp(socket.new_state()),
             crate::socket::Socket::Tcp(socket) => SocketState::Tcp(socket.new_state()),
+            crate::socket::Socket::Icmp(socket) => SocketState::Icmp(socket.new_state()),
             _ => unreachable!(),
         }
     }
@@ -257,6 +282,9 @@ impl<'a> TrackedSocket for crate::socket::Socket<'a> {
             (SocketState::Tcp(state), crate::socket::Socket::Tcp(socket)) => {
                 socket.on_drop(state, dispatch_table, handle)
             }
+            (SocketState::Icmp(state), crate::socket::Socket::Icmp(socket)) => {
+                socket.on_drop(state, dispatch_table, handle)
+            }
             _ => unreachable!(),
         }
     }
@@ -266,6 +294,7 @@ impl<'a> TrackedSocket for crate::socket::Socket<'a> {
             crate::socket::Socket::Raw(socket) => socket.is_dirty(),
             crate::socket::Socket::Udp(socket) => socket.is_dirty(),
             crate::socket::Socket::Tcp(socket) => socket.is_dirty(),
+            crate::socket::Socket::Icmp(socket) => socket.is_dirty(),
             _ => unreachable!(),
         }
     }
@@ -275,6 +304,7 @@ impl<'a> TrackedSocket for crate::socket::Socket<'a> {
             crate::socket::Socket::Raw(socket) => socket.is_on_dirty_list(),
             crate::socket::Socket::Udp(socket) => socket.is_on_dirty_list(),
             crate::socket::Socket::Tcp(socket) => socket.is_on_dirty_list(),
+            crate::socket::Socket::Icmp(socket) => socket.is_on_dirty_list(),
             _ => unreachable!(),
         }
     }
@@ -284,6 +314,7 @@ impl<'a> TrackedSocket for crate::socket::Socket<'a> {
             crate::socket::Socket::Raw(socket) => socket.set_on_dirty_list(is_dirty),
             crate::socket::Socket::Udp(socket) => socket.set_on_dirty_list(is_dirty),
             crate::socket::Socket::Tcp(socket) => socket.set_on_dirty_list(is_dirty),
+            crate::socket::Socket::Icmp(socket) => socket.set_on_dirty_list(is_dirty),
             _ => unreachable!(),
         }
     }
@@ -317,4 +348,4 @@ impl<'a, T: TrackedSocket + 'a> DerefMut for SocketTracker<'a, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.socket
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,44 @@
+use crate::wire::{IpListenEndpoint, IpVersion};
+
+/// What an ICMP socket is bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Unspecified,
+    /// Bound to an ICMP identifier, for routing echo replies.
+    Ident(u16),
+    /// Bound to a transport-layer endpoint, for routing ICMP error messages that
+    /// reference that flow.
+    Udp(IpListenEndpoint),
+}
+
+/// An ICMP (Internet Control Message Protocol) socket.
+#[derive(Debug)]
+pub struct Socket<'a> {
+    endpoint: Endpoint,
+    ip_version: IpVersion,
+    dirty: bool,
+    on_dirty_list: bool,
+    _buffers: core::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Socket<'a> {
+    pub fn endpoint(&self) -> Endpoint {
+        self.endpoint
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        self.ip_version
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn is_on_dirty_list(&self) -> bool {
+        self.on_dirty_list
+    }
+
+    pub(crate) fn set_on_dirty_list(&mut self, on_dirty_list: bool) {
+        self.on_dirty_list = on_dirty_list;
+    }
+}
@@ -0,0 +1,93 @@
+use crate::iface::DispatchTable;
+use crate::wire::{IpEndpoint, IpListenEndpoint, IpProtocol};
+
+/// The state of a TCP socket, according to [RFC 793].
+///
+/// [RFC 793]: https://datatracker.ietf.org/doc/html/rfc793
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// A Transmission Control Protocol socket.
+#[derive(Debug)]
+pub struct Socket<'a> {
+    state: State,
+    listen_endpoint: Option<IpListenEndpoint>,
+    local_endpoint: Option<IpEndpoint>,
+    remote_endpoint: Option<IpEndpoint>,
+    dual_stack: bool,
+    dirty: bool,
+    on_dirty_list: bool,
+    _buffers: core::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Socket<'a> {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn listen_endpoint(&self) -> Option<IpListenEndpoint> {
+        self.listen_endpoint
+    }
+
+    pub fn local_endpoint(&self) -> Option<IpEndpoint> {
+        self.local_endpoint
+    }
+
+    pub fn remote_endpoint(&self) -> Option<IpEndpoint> {
+        self.remote_endpoint
+    }
+
+    /// Returns whether this socket, when listening on the IPv6 unspecified address,
+    /// also accepts IPv4 connections addressed to its port.
+    pub fn is_dual_stack(&self) -> bool {
+        self.dual_stack
+    }
+
+    /// Enables or disables dual-stack mode. Only meaningful for a socket listening on
+    /// the IPv6 unspecified address.
+    pub fn set_dual_stack(&mut self, enabled: bool) {
+        self.dual_stack = enabled;
+    }
+
+    /// If connecting from a local endpoint with port 0, draws an ephemeral port from
+    /// `dispatch_table` and adopts it. No-op once the socket has a concrete port.
+    pub(crate) fn assign_ephemeral_port(&mut self, dispatch_table: &mut DispatchTable) {
+        let Some(local_endpoint) = self.local_endpoint else {
+            return;
+        };
+        if local_endpoint.port != 0 {
+            return;
+        }
+
+        if let Some(port) =
+            dispatch_table.allocate_ephemeral_port(IpProtocol::Tcp, local_endpoint.addr)
+        {
+            self.local_endpoint = Some(IpEndpoint::new(local_endpoint.addr, port));
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn is_on_dirty_list(&self) -> bool {
+        self.on_dirty_list
+    }
+
+    pub(crate) fn set_on_dirty_list(&mut self, on_dirty_list: bool) {
+        self.on_dirty_list = on_dirty_list;
+    }
+}
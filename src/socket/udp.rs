@@ -0,0 +1,65 @@
+use crate::iface::DispatchTable;
+use crate::wire::{IpAddress, IpListenEndpoint, IpProtocol, Ipv4Address};
+
+/// A User Datagram Protocol socket.
+#[derive(Debug)]
+pub struct Socket<'a> {
+    endpoint: IpListenEndpoint,
+    is_open: bool,
+    dual_stack: bool,
+    dirty: bool,
+    on_dirty_list: bool,
+    _buffers: core::marker::PhantomData<&'a ()>,
+}
+
+impl<'a> Socket<'a> {
+    pub fn endpoint(&self) -> IpListenEndpoint {
+        self.endpoint
+    }
+
+    /// Returns whether this socket, when bound to the IPv6 unspecified address, also
+    /// accepts IPv4 traffic addressed to its port.
+    pub fn is_dual_stack(&self) -> bool {
+        self.dual_stack
+    }
+
+    /// Enables or disables dual-stack mode. Only meaningful for a socket bound to the
+    /// IPv6 unspecified address.
+    pub fn set_dual_stack(&mut self, enabled: bool) {
+        self.dual_stack = enabled;
+    }
+
+    /// If bound with port 0, draws an ephemeral port from `dispatch_table` and adopts
+    /// it. No-op once the socket has a concrete port.
+    ///
+    /// A socket bound to `addr: None` (any local address) is tracked in the dispatch
+    /// table as a port-only binding regardless of IP version, so the address passed to
+    /// the allocator only needs to pick *a* version for the lookup, not the one the
+    /// socket will actually receive on.
+    pub(crate) fn assign_ephemeral_port(&mut self, dispatch_table: &mut DispatchTable) {
+        if self.endpoint.port != 0 {
+            return;
+        }
+        let addr = self
+            .endpoint
+            .addr
+            .unwrap_or(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED));
+
+        if let Some(port) = dispatch_table.allocate_ephemeral_port(IpProtocol::Udp, addr) {
+            self.endpoint.port = port;
+            self.dirty = true;
+        }
+    }
+
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub(crate) fn is_on_dirty_list(&self) -> bool {
+        self.on_dirty_list
+    }
+
+    pub(crate) fn set_on_dirty_list(&mut self, on_dirty_list: bool) {
+        self.on_dirty_list = on_dirty_list;
+    }
+}